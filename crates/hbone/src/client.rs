@@ -0,0 +1,308 @@
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::{Method, Request, Uri};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+use tracing::{debug, info, trace};
+
+use crate::{
+	Config, DropCounter, H2Stream, H2StreamReadHalf, H2StreamWriteHalf, KeepAlive, MetricsSink,
+	StreamMetricsState, WindowTuning, do_ping_pong, h2_to_io_error,
+};
+
+/// Whether the peer on the other end of a [Connection] has been observed to
+/// support RFC 8441 Extended CONNECT (`SETTINGS_ENABLE_CONNECT_PROTOCOL`).
+///
+/// `SETTINGS_ENABLE_CONNECT_PROTOCOL` is advertised by whichever side accepts extended CONNECT,
+/// i.e. the server; we only ever read it back off the handshake. Rather than failing a tunnel
+/// attempt outright when it's unknown, we probe on the first websocket stream and leave it up to the
+/// caller to fall back to a plain (non-websocket) CONNECT tunnel if that probe is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectProtocolState {
+	/// We haven't attempted an extended CONNECT (websocket) stream on this connection yet.
+	Unknown,
+	/// The peer has advertised `SETTINGS_ENABLE_CONNECT_PROTOCOL = 1`.
+	Supported,
+	/// The peer did not advertise support; don't bother trying again on this connection.
+	Unsupported,
+}
+
+impl ConnectProtocolState {
+	const fn as_u8(self) -> u8 {
+		match self {
+			ConnectProtocolState::Unknown => 0,
+			ConnectProtocolState::Supported => 1,
+			ConnectProtocolState::Unsupported => 2,
+		}
+	}
+
+	fn from_u8(v: u8) -> Self {
+		match v {
+			1 => ConnectProtocolState::Supported,
+			2 => ConnectProtocolState::Unsupported,
+			_ => ConnectProtocolState::Unknown,
+		}
+	}
+}
+
+/// A live HTTP/2 connection established for the purposes of opening HBONE tunnels.
+///
+/// Cheaply `Clone`-able (it is just a handle to the connection, like `h2::client::SendRequest`),
+/// so a pool can hand out the same connection to open multiple concurrent streams.
+#[derive(Clone)]
+pub struct Connection {
+	send_request: SendRequest<Bytes>,
+	active_count: Arc<AtomicU16>,
+	connect_protocol: Arc<AtomicU8>,
+	bytes_counter: Option<Arc<AtomicU64>>,
+	data_received: Arc<AtomicBool>,
+	metrics: Option<Arc<dyn MetricsSink>>,
+}
+
+/// Background task handles that keep a [Connection] alive. Dropping this leaves the tasks
+/// running detached; call [ConnectionDriver::abort] to force the connection closed, resetting
+/// any streams still open on it.
+pub struct ConnectionDriver {
+	_drive: tokio::task::JoinHandle<()>,
+	_ping: tokio::task::JoinHandle<()>,
+}
+
+impl ConnectionDriver {
+	/// Immediately tear down the connection, resetting any streams still open on it. Used by the
+	/// pool to close idle connections; a draining connection's deadline is enforced by its own
+	/// drive task instead.
+	pub fn abort(self) {
+		self._drive.abort();
+		self._ping.abort();
+	}
+}
+
+/// Handle to trigger graceful drain of a single client connection returned alongside it from
+/// [spawn]. Dropping this without calling [DrainHandle::drain] has no effect; the connection
+/// just keeps being usable normally.
+pub struct DrainHandle(oneshot::Sender<Duration>);
+
+impl DrainHandle {
+	/// Stop the connection driver once all in-flight streams finish, or forcibly close it
+	/// (resetting whatever streams remain) if `deadline` elapses first.
+	///
+	/// Unlike the server side, there is no peer-visible GOAWAY to send here: the client is the
+	/// only one that ever opens streams on this connection, so refusing to open new ones (the
+	/// pool already stops handing the connection out once it's draining) is the whole of
+	/// "stop accepting new work" from this end; nothing needs to be advertised to the peer.
+	pub fn drain(self, deadline: Duration) {
+		let _ = self.0.send(deadline);
+	}
+}
+
+/// Establish a new HTTP/2 client connection over `io`, to be used for opening HBONE tunnels.
+///
+/// `metrics`, if set, receives per-stream byte/duration stats and per-connection ping RTT
+/// samples for every stream opened on the resulting connection.
+pub async fn spawn<I>(
+	io: I,
+	config: &Config,
+	metrics: Option<Arc<dyn MetricsSink>>,
+) -> Result<(Connection, ConnectionDriver, DrainHandle), Error>
+where
+	I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	// `enable_connect_protocol` lives on `server::Builder` only: SETTINGS_ENABLE_CONNECT_PROTOCOL
+	// is advertised by the accepting side, so the client has nothing of its own to opt into here
+	// and just reads the peer's setting back below.
+	let (send_request, mut connection) = h2::client::Builder::new()
+		.initial_window_size(config.window_size)
+		.initial_connection_window_size(config.connection_window_size)
+		.max_frame_size(config.frame_size)
+		.handshake(io)
+		.await
+		.map_err(h2_to_io_error)?;
+
+	let peer_connect_protocol = connection.extended_connect_protocol_enabled();
+
+	let active_count: Arc<AtomicU16> = Arc::new(AtomicU16::new(0));
+	let dropped = Arc::new(AtomicBool::new(false));
+	let ping_pong = connection
+		.ping_pong()
+		.expect("ping_pong should only be taken once");
+	let (ping_tx, ping_rx) = oneshot::channel();
+
+	let (window_tuning, mut window_updates, bytes_counter) = if config.adaptive_window {
+		let (tuning, rx) = WindowTuning::new(config.window_size, config.max_window_size);
+		let bytes_counter = tuning.bytes_counter();
+		(Some(tuning), Some(rx), Some(bytes_counter))
+	} else {
+		(None, None, None)
+	};
+	let (keep_alive, data_received) = KeepAlive::new(config, active_count.clone());
+	let ping = tokio::spawn(do_ping_pong(
+		ping_pong,
+		ping_tx,
+		dropped.clone(),
+		window_tuning,
+		keep_alive,
+		metrics.clone(),
+	));
+
+	let (drain_tx, drain_rx) = oneshot::channel::<Duration>();
+	let drive_active_count = active_count.clone();
+	let drive = tokio::spawn(async move {
+		let mut drain_rx = Some(drain_rx);
+		// Set once draining has started; ticks down the bounded deadline for in-flight streams.
+		let mut drain_deadline: Option<Pin<Box<tokio::time::Sleep>>> = None;
+		loop {
+			tokio::select! {
+				res = &mut connection => {
+					if let Err(e) = res {
+						debug!("connection failed: {e}");
+					}
+					break;
+				},
+				_ = &mut ping_rx => {
+					trace!("connection driver exiting due to ping timeout");
+					break;
+				},
+				Some(target) = async { window_updates.as_mut()?.recv().await }, if window_updates.is_some() => {
+					connection.set_target_window_size(target);
+					let _ = connection.set_initial_window_size(target);
+				},
+				Some(deadline) = async { drain_rx.as_mut()?.await.ok() }, if drain_rx.is_some() => {
+					drain_rx = None;
+					info!("draining connection, waiting up to {deadline:?} for {} active streams", drive_active_count.load(Ordering::Relaxed));
+					drain_deadline = Some(Box::pin(tokio::time::sleep(deadline)));
+				},
+				_ = async { drain_deadline.as_mut().unwrap().await }, if drain_deadline.is_some() => {
+					trace!("drain deadline elapsed with streams still active, closing the connection out from under them");
+					break;
+				},
+			}
+
+			if drain_deadline.is_some() && drive_active_count.load(Ordering::Relaxed) == 0 {
+				trace!("all streams finished draining");
+				break;
+			}
+		}
+		dropped.store(true, Ordering::Relaxed);
+	});
+
+	let connect_protocol = Arc::new(AtomicU8::new(
+		if peer_connect_protocol {
+			ConnectProtocolState::Supported
+		} else {
+			ConnectProtocolState::Unknown
+		}
+		.as_u8(),
+	));
+
+	Ok((
+		Connection {
+			send_request,
+			active_count,
+			connect_protocol,
+			bytes_counter,
+			data_received,
+			metrics,
+		},
+		ConnectionDriver {
+			_drive: drive,
+			_ping: ping,
+		},
+		DrainHandle(drain_tx),
+	))
+}
+
+impl Connection {
+	/// Number of HBONE streams currently open on this connection.
+	pub fn active_streams(&self) -> u16 {
+		self.active_count.load(Ordering::Relaxed)
+	}
+
+	/// Open a plain HBONE tunnel (raw bytes CONNECT) to `authority`.
+	pub async fn connect(&mut self, authority: &str) -> Result<H2Stream, Error> {
+		let req = Request::builder()
+			.method(Method::CONNECT)
+			.uri(Uri::builder().authority(authority).path_and_query("/").build().map_err(Error::other)?)
+			.body(())
+			.map_err(Error::other)?;
+		self.send(req).await
+	}
+
+	/// Open a WebSocket-over-HTTP/2 tunnel (RFC 8441 Extended CONNECT) to `authority`.
+	///
+	/// If the peer is not known to support `SETTINGS_ENABLE_CONNECT_PROTOCOL`, this probes by
+	/// attempting it anyway on [ConnectProtocolState::Unknown]; callers that want a hard
+	/// guarantee should check [Connection::supports_websocket] first and fall back to
+	/// [Connection::connect] themselves.
+	pub async fn connect_websocket(&mut self, authority: &str) -> Result<H2Stream, Error> {
+		let state = ConnectProtocolState::from_u8(self.connect_protocol.load(Ordering::Acquire));
+		if state == ConnectProtocolState::Unsupported {
+			return Err(Error::other("peer does not support extended CONNECT"));
+		}
+		let mut req = Request::builder()
+			.method(Method::CONNECT)
+			.uri(Uri::builder().authority(authority).path_and_query("/").build().map_err(Error::other)?)
+			.body(())
+			.map_err(Error::other)?;
+		req.extensions_mut().insert(h2::ext::Protocol::from_static("websocket"));
+
+		let result = self.send(req).await;
+		if state == ConnectProtocolState::Unknown {
+			let learned = if result.is_ok() {
+				ConnectProtocolState::Supported
+			} else {
+				ConnectProtocolState::Unsupported
+			};
+			self.connect_protocol.store(learned.as_u8(), Ordering::Release);
+		}
+		result
+	}
+
+	/// Whether this connection is known to support extended CONNECT (websocket tunneling).
+	/// Returns `false` both when the peer has rejected it and when it hasn't been probed yet;
+	/// use [Connection::connect_websocket] to probe. A failed probe returns an error; falling back
+	/// to [Connection::connect] is left to the caller.
+	pub fn supports_websocket(&self) -> bool {
+		ConnectProtocolState::from_u8(self.connect_protocol.load(Ordering::Acquire))
+			== ConnectProtocolState::Supported
+	}
+
+	async fn send(&mut self, req: Request<()>) -> Result<H2Stream, Error> {
+		self.send_request.ready().await.map_err(h2_to_io_error)?;
+		let (response, send_stream) = self
+			.send_request
+			.send_request(req, false)
+			.map_err(h2_to_io_error)?;
+		let response = response.await.map_err(h2_to_io_error)?;
+		if !response.status().is_success() {
+			return Err(Error::other(format!(
+				"tunnel rejected with status {}",
+				response.status()
+			)));
+		}
+		let recv_stream = response.into_body();
+
+		self.active_count.fetch_add(1, Ordering::SeqCst);
+		let stream_metrics = self.metrics.clone().map(|sink| (StreamMetricsState::new(), sink));
+		let (d1, d2) = DropCounter::new(self.active_count.clone(), stream_metrics.clone());
+		Ok(H2Stream {
+			read: H2StreamReadHalf {
+				recv_stream,
+				_dropped: d1,
+				conn_bytes_counter: self.bytes_counter.clone(),
+				conn_data_received: self.data_received.clone(),
+				metrics: stream_metrics.as_ref().map(|(state, _)| state.clone()),
+			},
+			write: H2StreamWriteHalf {
+				send_stream,
+				_dropped: d2,
+				metrics: stream_metrics.map(|(state, _)| state),
+			},
+		})
+	}
+}
@@ -4,15 +4,15 @@ use std::io::Error;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use agent_core::copy;
 use agent_core::prelude::*;
 use bytes::{BufMut, Bytes};
 use h2::Reason;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 use tracing::trace;
 
 pub mod client;
@@ -31,24 +31,157 @@ pub struct Config {
 	pub frame_size: u32,
 	pub pool_max_streams_per_conn: u16,
 	pub pool_unused_release_timeout: Duration,
+	/// When set, the stream/connection flow-control windows start at `window_size`/
+	/// `connection_window_size` but are grown automatically based on a bandwidth-delay-product
+	/// estimate taken from the keepalive ping RTT, up to `max_window_size`. This avoids the
+	/// default window becoming a throughput bottleneck on high-latency links.
+	pub adaptive_window: bool,
+	/// Ceiling for the auto-tuned window when `adaptive_window` is enabled. Ignored otherwise.
+	pub max_window_size: u32,
+	/// How often to check whether a keepalive ping is needed. A ping is skipped (and this timer
+	/// simply reset) if any stream on the connection received data since the last check.
+	pub keep_alive_interval: Duration,
+	/// How long to wait for a pong before considering the connection dead.
+	pub keep_alive_timeout: Duration,
+	/// Whether to keep sending pings on a connection with no open streams. If `false`, an idle
+	/// connection with no active streams goes quiet rather than generating needless PING frames;
+	/// pings resume at the first check after a new stream is opened.
+	pub keep_alive_while_idle: bool,
+}
+
+/// Per-connection state shared between `do_ping_pong` and every stream opened on the
+/// connection, used to drive [Config::adaptive_window] BDP estimation.
+pub(crate) struct WindowTuning {
+	/// Bytes received across all streams on the connection since the last ping was sent.
+	/// Reset to zero when a ping is sent, and read (and reset again) when its pong comes back.
+	bytes_since_ping: Arc<AtomicU64>,
+	/// Sink for new target window sizes; the connection driver applies them via
+	/// `set_target_window_size` since only the task polling the connection can mutate it.
+	new_target: mpsc::UnboundedSender<u32>,
+	initial_window: u32,
+	max_window_size: u32,
+}
+
+impl WindowTuning {
+	pub(crate) fn new(initial_window: u32, max_window_size: u32) -> (Self, mpsc::UnboundedReceiver<u32>) {
+		let (new_target, rx) = mpsc::unbounded_channel();
+		(
+			Self {
+				bytes_since_ping: Arc::new(AtomicU64::new(0)),
+				new_target,
+				initial_window,
+				max_window_size: max_window_size.max(initial_window),
+			},
+			rx,
+		)
+	}
+
+	pub(crate) fn bytes_counter(&self) -> Arc<AtomicU64> {
+		self.bytes_since_ping.clone()
+	}
+}
+
+/// Tracks the running bandwidth-delay-product estimate used to grow a connection's flow
+/// control window. We only ever grow the window (never shrink), doubling toward the
+/// estimated target each round to avoid thrashing on a single noisy RTT sample.
+struct BdpEstimator {
+	max_bandwidth_bytes_per_sec: f64,
+	current_window: u32,
+}
+
+impl BdpEstimator {
+	fn new(initial_window: u32) -> Self {
+		Self {
+			max_bandwidth_bytes_per_sec: 0.0,
+			current_window: initial_window,
+		}
+	}
+
+	/// Feed a new `(bytes received since last ping, measured RTT)` sample. Returns `Some(window)`
+	/// if the window should grow to `window`, or `None` if there's nothing useful to do yet.
+	fn sample(&mut self, bytes_received: u64, rtt: Duration, max_window: u32) -> Option<u32> {
+		if bytes_received == 0 || rtt.is_zero() {
+			return None;
+		}
+		let bandwidth = bytes_received as f64 / rtt.as_secs_f64();
+		self.max_bandwidth_bytes_per_sec = self.max_bandwidth_bytes_per_sec.max(bandwidth);
+		let target = (self.max_bandwidth_bytes_per_sec * rtt.as_secs_f64()).min(max_window as f64) as u32;
+		if target <= self.current_window {
+			return None;
+		}
+		let next = self.current_window.saturating_mul(2).min(target);
+		if next <= self.current_window {
+			return None;
+		}
+		self.current_window = next;
+		Some(next)
+	}
+}
+
+/// Per-connection state shared between `do_ping_pong` and every stream opened on the
+/// connection, used to make keepalive pings data-aware: a ping is only worth sending if
+/// the peer hasn't otherwise proven it's alive by sending us data.
+pub(crate) struct KeepAlive {
+	pub(crate) interval: Duration,
+	pub(crate) timeout: Duration,
+	pub(crate) while_idle: bool,
+	/// Set by `H2StreamReadHalf::poll_bytes` whenever data arrives on any stream, and cleared
+	/// by the ping loop each time it checks whether a ping is needed.
+	pub(crate) data_received: Arc<AtomicBool>,
+	/// Number of currently-open streams on the connection; used to suppress pings entirely on
+	/// an idle connection when `while_idle` is false.
+	pub(crate) active_count: Arc<AtomicU16>,
+}
+
+impl KeepAlive {
+	pub(crate) fn new(config: &Config, active_count: Arc<AtomicU16>) -> (Self, Arc<AtomicBool>) {
+		let data_received = Arc::new(AtomicBool::new(false));
+		(
+			Self {
+				interval: config.keep_alive_interval,
+				timeout: config.keep_alive_timeout,
+				while_idle: config.keep_alive_while_idle,
+				data_received: data_received.clone(),
+				active_count,
+			},
+			data_received,
+		)
+	}
 }
 
 async fn do_ping_pong(
 	mut ping_pong: h2::PingPong,
 	tx: oneshot::Sender<()>,
 	dropped: Arc<AtomicBool>,
+	window_tuning: Option<WindowTuning>,
+	keep_alive: KeepAlive,
+	metrics: Option<Arc<dyn MetricsSink>>,
 ) {
-	const PING_INTERVAL: Duration = Duration::from_secs(10);
-	const PING_TIMEOUT: Duration = Duration::from_secs(20);
-	// delay before sending the first ping, no need to race with the first request
-	tokio::time::sleep(PING_INTERVAL).await;
+	let mut bdp = window_tuning.as_ref().map(|w| BdpEstimator::new(w.initial_window));
 	loop {
+		// delay before checking whether a ping is needed, no need to race with the first request
+		tokio::time::sleep(keep_alive.interval).await;
 		if dropped.load(Ordering::Relaxed) {
 			return;
 		}
+		if keep_alive.data_received.swap(false, Ordering::Relaxed) {
+			// Hyper and Go both skip pings when data is actively flowing, since receiving data
+			// already proves the peer is alive; this avoids needless PING round-trips.
+			trace!("data received since last check, skipping keepalive ping");
+			continue;
+		}
+		if !keep_alive.while_idle && keep_alive.active_count.load(Ordering::Relaxed) == 0 {
+			trace!("connection idle with no open streams, skipping keepalive ping");
+			continue;
+		}
+		if let Some(tuning) = window_tuning.as_ref() {
+			// Only bytes that arrive while this ping is in flight count toward the BDP sample.
+			tuning.bytes_since_ping.store(0, Ordering::Relaxed);
+		}
+		let sent_at = Instant::now();
 		let ping_fut = ping_pong.ping(h2::Ping::opaque());
 		trace!("ping sent");
-		match tokio::time::timeout(PING_TIMEOUT, ping_fut).await {
+		match tokio::time::timeout(keep_alive.timeout, ping_fut).await {
 			Err(_) => {
 				// We will log this again up in drive_connection, so don't worry about a high log level
 				trace!("ping timeout");
@@ -58,7 +191,17 @@ async fn do_ping_pong(
 			Ok(r) => match r {
 				Ok(_) => {
 					trace!("pong received");
-					tokio::time::sleep(PING_INTERVAL).await;
+					let rtt = sent_at.elapsed();
+					if let Some(sink) = &metrics {
+						sink.record_rtt(rtt);
+					}
+					if let (Some(tuning), Some(estimator)) = (window_tuning.as_ref(), bdp.as_mut()) {
+						let bytes = tuning.bytes_since_ping.swap(0, Ordering::Relaxed);
+						if let Some(target) = estimator.sample(bytes, rtt, tuning.max_window_size) {
+							trace!("growing flow control window to {target} (rtt={rtt:?}, bytes={bytes})");
+							let _ = tuning.new_target.send(target);
+						}
+					}
 				},
 				Err(e) => {
 					if dropped.load(Ordering::Relaxed) {
@@ -119,6 +262,48 @@ impl tokio::io::AsyncWrite for RWStream {
 		Pin::new(&mut self.stream.write).poll_shutdown(cx)
 	}
 }
+
+/// Observes per-stream and per-connection HBONE metrics. Implementations typically forward
+/// these into whatever metrics system the embedding application uses; kept as a trait rather
+/// than a dependency on any particular metrics library so this crate stays agnostic to it.
+pub trait MetricsSink: Send + Sync {
+	/// Called once both halves of a stream have dropped, with its final lifecycle stats.
+	fn record_stream(&self, metrics: StreamMetrics);
+	/// Called each time a keepalive ping round-trip completes on a connection.
+	fn record_rtt(&self, rtt: Duration);
+}
+
+/// Final per-stream stats reported to a [MetricsSink] when a stream closes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamMetrics {
+	pub bytes_read: u64,
+	pub bytes_written: u64,
+	pub duration: Duration,
+	/// Number of times `poll_capacity` returned `Pending`, i.e. the stream was blocked on
+	/// flow control waiting for the peer to free up send window.
+	pub write_blocked_count: u32,
+}
+
+// Shared between a stream's read and write halves (and the `DropCounter` that emits the final
+// record), so either side can update its own counters independently.
+struct StreamMetricsState {
+	opened_at: Instant,
+	bytes_read: AtomicU64,
+	bytes_written: AtomicU64,
+	write_blocked_count: AtomicU32,
+}
+
+impl StreamMetricsState {
+	fn new() -> Arc<Self> {
+		Arc::new(Self {
+			opened_at: Instant::now(),
+			bytes_read: AtomicU64::new(0),
+			bytes_written: AtomicU64::new(0),
+			write_blocked_count: AtomicU32::new(0),
+		})
+	}
+}
+
 // H2Stream represents an active HTTP2 stream. Consumers can only Read/Write
 pub struct H2Stream {
 	read: H2StreamReadHalf,
@@ -128,11 +313,22 @@ pub struct H2Stream {
 pub struct H2StreamReadHalf {
 	recv_stream: h2::RecvStream,
 	_dropped: Option<DropCounter>,
+	// Shared with the connection's ping task when `Config::adaptive_window` is enabled, so it
+	// can estimate bandwidth-delay-product from bytes received across all streams. `None` when
+	// adaptive windowing is off.
+	conn_bytes_counter: Option<Arc<AtomicU64>>,
+	// Shared with the connection's ping task so keepalive pings can be skipped while data is
+	// actively flowing; see `KeepAlive`.
+	conn_data_received: Arc<AtomicBool>,
+	// `None` unless a `MetricsSink` was configured for the connection.
+	metrics: Option<Arc<StreamMetricsState>>,
 }
 
 pub struct H2StreamWriteHalf {
 	send_stream: h2::SendStream<Bytes>,
 	_dropped: Option<DropCounter>,
+	// `None` unless a `MetricsSink` was configured for the connection.
+	metrics: Option<Arc<StreamMetricsState>>,
 }
 
 pub struct TokioH2Stream(H2Stream);
@@ -142,18 +338,24 @@ struct DropCounter {
 	// We only decrement if they have, so we do not double count
 	half_dropped: Arc<()>,
 	active_count: Arc<AtomicU16>,
+	metrics: Option<(Arc<StreamMetricsState>, Arc<dyn MetricsSink>)>,
 }
 
 impl DropCounter {
-	pub fn new(active_count: Arc<AtomicU16>) -> (Option<DropCounter>, Option<DropCounter>) {
+	pub fn new(
+		active_count: Arc<AtomicU16>,
+		metrics: Option<(Arc<StreamMetricsState>, Arc<dyn MetricsSink>)>,
+	) -> (Option<DropCounter>, Option<DropCounter>) {
 		let half_dropped = Arc::new(());
 		let d1 = DropCounter {
 			half_dropped: half_dropped.clone(),
 			active_count: active_count.clone(),
+			metrics: metrics.clone(),
 		};
 		let d2 = DropCounter {
 			half_dropped,
 			active_count,
+			metrics,
 		};
 		(Some(d1), Some(d2))
 	}
@@ -185,6 +387,14 @@ impl Drop for DropCounter {
 			// other half already dropped
 			let left = self.active_count.fetch_sub(1, Ordering::SeqCst);
 			trace!("dropping H2Stream, has {} active streams left", left - 1);
+			if let Some((state, sink)) = &self.metrics {
+				sink.record_stream(StreamMetrics {
+					bytes_read: state.bytes_read.load(Ordering::Relaxed),
+					bytes_written: state.bytes_written.load(Ordering::Relaxed),
+					duration: state.opened_at.elapsed(),
+					write_blocked_count: state.write_blocked_count.load(Ordering::Relaxed),
+				});
+			}
 		} else {
 			trace!("dropping H2Stream, other half remains");
 		}
@@ -259,10 +469,13 @@ impl copy::ResizeBufRead for H2StreamReadHalf {
 				None => return Poll::Ready(Ok(Bytes::new())),
 				Some(Ok(buf)) if buf.is_empty() && !this.recv_stream.is_end_stream() => continue,
 				Some(Ok(buf)) => {
-					// TODO: Hyper and Go make their pinging data aware and don't send pings when data is received
-					// Pingora, and our implementation, currently don't do this.
-					// We may want to; if so, modify here.
-					// this.ping.record_data(buf.len());
+					this.conn_data_received.store(true, Ordering::Relaxed);
+					if let Some(counter) = &this.conn_bytes_counter {
+						counter.fetch_add(buf.len() as u64, Ordering::Relaxed);
+					}
+					if let Some(metrics) = &this.metrics {
+						metrics.bytes_read.fetch_add(buf.len() as u64, Ordering::Relaxed);
+					}
 					let _ = this.recv_stream.flow_control().release_capacity(buf.len());
 					return Poll::Ready(Ok(buf));
 				},
@@ -297,13 +510,22 @@ impl copy::AsyncWriteBuf for H2StreamWriteHalf {
 
 		// We ignore all errors returned by `poll_capacity` and `write`, as we
 		// will get the correct from `poll_reset` anyway.
-		let cnt = match ready!(self.send_stream.poll_capacity(cx)) {
+		let capacity = self.send_stream.poll_capacity(cx);
+		if capacity.is_pending() {
+			if let Some(metrics) = &self.metrics {
+				metrics.write_blocked_count.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+		let cnt = match ready!(capacity) {
 			None => Some(0),
 			Some(Ok(cnt)) => self.write_slice(buf.slice(..cnt), false).ok().map(|()| cnt),
 			Some(Err(_)) => None,
 		};
 
 		if let Some(cnt) = cnt {
+			if let Some(metrics) = &self.metrics {
+				metrics.bytes_written.fetch_add(cnt as u64, Ordering::Relaxed);
+			}
 			return Poll::Ready(Ok(cnt));
 		}
 
@@ -351,3 +573,79 @@ fn h2_to_io_error(e: h2::Error) -> std::io::Error {
 		std::io::Error::other(e)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bdp_estimator_grows_monotonically_toward_steady_bandwidth() {
+		let mut bdp = BdpEstimator::new(65_535);
+		let rtt = Duration::from_millis(100);
+		let max_window = 10_000_000;
+		// 10MB/s for 100ms of RTT is a 1MB bandwidth-delay-product target.
+		let bytes_received = 1_000_000;
+
+		let mut last = 65_535;
+		let mut grew_at_least_once = false;
+		for _ in 0..10 {
+			match bdp.sample(bytes_received, rtt, max_window) {
+				Some(next) => {
+					assert!(next > last, "window should only ever grow: {next} <= {last}");
+					last = next;
+					grew_at_least_once = true;
+				},
+				None => break,
+			}
+		}
+		assert!(grew_at_least_once);
+		assert_eq!(last, 1_000_000);
+		// Once at the target, a further identical sample has nothing left to grow toward.
+		assert_eq!(bdp.sample(bytes_received, rtt, max_window), None);
+	}
+
+	#[test]
+	fn bdp_estimator_ignores_zero_bytes_or_zero_rtt() {
+		let mut bdp = BdpEstimator::new(65_535);
+		assert_eq!(bdp.sample(0, Duration::from_millis(100), 10_000_000), None);
+		assert_eq!(bdp.sample(1_000_000, Duration::ZERO, 10_000_000), None);
+		assert_eq!(bdp.current_window, 65_535);
+	}
+
+	#[test]
+	fn bdp_estimator_clamps_growth_at_max_window_size() {
+		let mut bdp = BdpEstimator::new(65_535);
+		let rtt = Duration::from_millis(100);
+		let max_window = 200_000;
+		// A bandwidth-delay product far above max_window should only ever grow us up to it.
+		let bytes_received = 50_000_000;
+
+		let mut last = 65_535;
+		loop {
+			match bdp.sample(bytes_received, rtt, max_window) {
+				Some(next) => {
+					assert!(next <= max_window);
+					last = next;
+				},
+				None => break,
+			}
+		}
+		assert_eq!(last, max_window);
+	}
+
+	#[test]
+	fn bdp_estimator_never_shrinks_on_a_lower_sample() {
+		let mut bdp = BdpEstimator::new(65_535);
+		let max_window = 10_000_000;
+
+		let grown = bdp
+			.sample(1_000_000, Duration::from_millis(100), max_window)
+			.expect("should grow from the initial window");
+		assert!(grown > 65_535);
+
+		// A much smaller bandwidth-delay product than the window we already grew to must not
+		// shrink it back down.
+		assert_eq!(bdp.sample(1, Duration::from_millis(1), max_window), None);
+		assert_eq!(bdp.current_window, grown);
+	}
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::trace;
+
+use crate::client::{self, Connection, ConnectionDriver, DrainHandle};
+use crate::{Config, Key, MetricsSink};
+
+struct PooledConnection {
+	conn: Connection,
+	// Kept around purely so we can `abort()` it to force-close an idle connection; the
+	// background ping/drive tasks themselves run detached regardless of whether we hold onto
+	// this.
+	driver: ConnectionDriver,
+	// Consumed by `drain_all` to hand the connection's own drive task a deadline; the drive task
+	// then closes the connection itself once streams finish or the deadline elapses.
+	drain: DrainHandle,
+	last_used: Instant,
+	// Set once this connection has been asked to drain; such connections are no longer handed
+	// out for new streams even if they have spare capacity.
+	draining: bool,
+}
+
+/// A pool of multiplexed HTTP/2 connections, keyed by destination.
+///
+/// Streams are multiplexed onto an existing connection for the same key as long as it has
+/// capacity (`pool_max_streams_per_conn`); once a connection is saturated, or idle for longer
+/// than `pool_unused_release_timeout`, it is evicted and a fresh one is dialed on next use.
+pub struct Pool<K: Key> {
+	config: Config,
+	metrics: Option<Arc<dyn MetricsSink>>,
+	connections: Arc<Mutex<HashMap<K, PooledConnection>>>,
+}
+
+impl<K: Key> Pool<K> {
+	pub fn new(config: Config) -> Self {
+		Self {
+			config,
+			metrics: None,
+			connections: Default::default(),
+		}
+	}
+
+	/// Attach a [MetricsSink] that every connection dialed by this pool (and every stream opened
+	/// on them) will report byte/latency stats to.
+	pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> Self {
+		self.metrics = Some(metrics);
+		self
+	}
+
+	/// Get a connection usable for a new stream to `key`, reusing a pooled one if one exists
+	/// with spare capacity, or dialing a new one via `dial` otherwise.
+	pub async fn connect<I, D, F>(&self, key: &K, dial: D) -> Result<Connection, Error>
+	where
+		I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+		D: FnOnce() -> F,
+		F: std::future::Future<Output = Result<I, Error>>,
+	{
+		self.evict_idle().await;
+
+		let mut connections = self.connections.lock().await;
+		if let Some(pooled) = connections.get_mut(key) {
+			if !pooled.draining && pooled.conn.active_streams() < self.config.pool_max_streams_per_conn {
+				pooled.last_used = Instant::now();
+				return Ok(pooled.conn.clone());
+			}
+			trace!("pooled connection to {key} saturated or draining, dialing a new one");
+		}
+		drop(connections);
+
+		let io = dial().await?;
+		let (conn, driver, drain) = client::spawn(io, &self.config, self.metrics.clone()).await?;
+
+		let mut connections = self.connections.lock().await;
+		connections.insert(
+			key.clone(),
+			PooledConnection {
+				conn: conn.clone(),
+				driver,
+				drain,
+				last_used: Instant::now(),
+				draining: false,
+			},
+		);
+		Ok(conn)
+	}
+
+	/// Gracefully drain every pooled connection: stop handing any of them out for new streams,
+	/// and close each one as soon as it goes idle. Connections already idle are closed right
+	/// away (per [Config::pool_unused_release_timeout] semantics); busy connections are handed
+	/// off to their own drive task (see [client::DrainHandle]), which gives them up to `deadline`
+	/// to finish their in-flight streams before force-closing, resetting whatever streams remain.
+	pub async fn drain_all(&self, deadline: Duration) {
+		let mut connections = self.connections.lock().await;
+		let draining: Vec<_> = connections.drain().map(|(_, pooled)| pooled).collect();
+		drop(connections);
+
+		for mut pooled in draining {
+			pooled.draining = true;
+			if pooled.conn.active_streams() == 0 {
+				trace!("closing idle pooled connection during drain");
+				pooled.driver.abort();
+				continue;
+			}
+			trace!(
+				"draining pooled connection, waiting up to {deadline:?} for {} active streams",
+				pooled.conn.active_streams()
+			);
+			pooled.drain.drain(deadline);
+		}
+	}
+
+	/// Drop any connections that have had no new streams opened against them for longer than
+	/// `pool_unused_release_timeout`. Busy connections (with active streams) are left alone
+	/// regardless of age; they will be cleaned up once their last stream closes and they go idle.
+	async fn evict_idle(&self) {
+		let mut connections = self.connections.lock().await;
+		let stale: Vec<K> = connections
+			.iter()
+			.filter(|(_, pooled)| {
+				pooled.last_used.elapsed() >= self.config.pool_unused_release_timeout
+					&& pooled.conn.active_streams() == 0
+			})
+			.map(|(key, _)| key.clone())
+			.collect();
+		for key in stale {
+			trace!("releasing idle pooled connection to {key}");
+			if let Some(pooled) = connections.remove(&key) {
+				pooled.driver.abort();
+			}
+		}
+	}
+
+	/// Number of distinct destinations with a pooled connection.
+	pub async fn len(&self) -> usize {
+		self.connections.lock().await.len()
+	}
+}
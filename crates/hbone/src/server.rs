@@ -0,0 +1,220 @@
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use h2::server::SendResponse;
+use http::{Method, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, trace};
+
+use crate::{
+	Config, DropCounter, H2Stream, H2StreamReadHalf, H2StreamWriteHalf, KeepAlive, MetricsSink,
+	StreamMetricsState, WindowTuning, do_ping_pong, h2_to_io_error,
+};
+
+/// Handle to trigger graceful shutdown of a single server connection returned alongside it
+/// from [spawn]. Dropping this without calling [DrainHandle::drain] has no effect; the
+/// connection just keeps serving normally.
+pub struct DrainHandle(oneshot::Sender<Duration>);
+
+impl DrainHandle {
+	/// Stop accepting new streams on the connection, send a GOAWAY, and wait for in-flight
+	/// streams to finish before closing.
+	///
+	/// `deadline` is best-effort, not a hard bound: once it elapses we stop polling the
+	/// connection and drop it, which closes the underlying transport, but any handler task still
+	/// parked on a stream it holds (e.g. in `poll_data`/`poll_capacity`) only observes that as a
+	/// connection-reset error the next time it's polled, not immediately. There is no per-stream
+	/// registry here to proactively `RST_STREAM` each outstanding handler on expiry.
+	pub fn drain(self, deadline: Duration) {
+		let _ = self.0.send(deadline);
+	}
+}
+
+/// A server-side HTTP/2 connection ready to serve HBONE tunnels, along with the bookkeeping
+/// set up by [spawn] (active stream tracking, keepalive, adaptive window tuning).
+pub struct Server<I> {
+	connection: h2::server::Connection<I, Bytes>,
+	active_count: Arc<AtomicU16>,
+	dropped: Arc<AtomicBool>,
+	ping_rx: oneshot::Receiver<()>,
+	window_updates: Option<mpsc::UnboundedReceiver<u32>>,
+	bytes_counter: Option<Arc<AtomicU64>>,
+	data_received: Arc<AtomicBool>,
+	drain_rx: oneshot::Receiver<Duration>,
+	metrics: Option<Arc<dyn MetricsSink>>,
+}
+
+/// Perform the HTTP/2 server handshake on `io`, advertising support for RFC 8441 Extended
+/// CONNECT (websocket tunnels) alongside plain CONNECT tunnels, and start the connection's
+/// keepalive ping loop. Call [Server::serve] on the result to actually accept tunnels.
+///
+/// `metrics`, if set, receives per-stream byte/duration stats and per-connection ping RTT
+/// samples for every tunnel accepted on the resulting connection.
+pub async fn spawn<I>(
+	io: I,
+	config: &Config,
+	metrics: Option<Arc<dyn MetricsSink>>,
+) -> Result<(Server<I>, DrainHandle), Error>
+where
+	I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let mut connection = h2::server::Builder::new()
+		.initial_window_size(config.window_size)
+		.initial_connection_window_size(config.connection_window_size)
+		.max_frame_size(config.frame_size)
+		.enable_connect_protocol()
+		.handshake(io)
+		.await
+		.map_err(h2_to_io_error)?;
+
+	let active_count: Arc<AtomicU16> = Arc::new(AtomicU16::new(0));
+	let dropped = Arc::new(AtomicBool::new(false));
+	let ping_pong = connection
+		.ping_pong()
+		.expect("ping_pong should only be taken once");
+	let (ping_tx, ping_rx) = oneshot::channel();
+
+	let (window_tuning, window_updates, bytes_counter) = if config.adaptive_window {
+		let (tuning, rx) = WindowTuning::new(config.window_size, config.max_window_size);
+		let bytes_counter = tuning.bytes_counter();
+		(Some(tuning), Some(rx), Some(bytes_counter))
+	} else {
+		(None, None, None)
+	};
+	let (keep_alive, data_received) = KeepAlive::new(config, Arc::clone(&active_count));
+	tokio::spawn(do_ping_pong(
+		ping_pong,
+		ping_tx,
+		dropped.clone(),
+		window_tuning,
+		keep_alive,
+		metrics.clone(),
+	));
+
+	let (drain_tx, drain_rx) = oneshot::channel();
+
+	Ok((
+		Server {
+			connection,
+			active_count,
+			dropped,
+			ping_rx,
+			window_updates,
+			bytes_counter,
+			data_received,
+			drain_rx,
+			metrics,
+		},
+		DrainHandle(drain_tx),
+	))
+}
+
+impl<I> Server<I>
+where
+	I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	/// Accept and serve HBONE tunnels until the connection closes, the peer goes quiet past the
+	/// keepalive timeout, or a [DrainHandle::drain] deadline elapses. `handler` is invoked for
+	/// each accepted tunnel; it is expected to spawn its own task if it needs to avoid blocking
+	/// acceptance of subsequent streams.
+	pub async fn serve<F>(mut self, handler: F) -> Result<(), Error>
+	where
+		F: Fn(H2Stream, bool) + Send + 'static,
+	{
+		let mut drain_rx = Some(self.drain_rx);
+		// Set once draining has started; ticks down the bounded deadline for in-flight streams.
+		let mut drain_deadline: Option<Pin<Box<tokio::time::Sleep>>> = None;
+
+		let result = loop {
+			tokio::select! {
+				next = self.connection.accept() => {
+					let Some(next) = next else {
+						break Ok(());
+					};
+					let (req, respond) = match next.map_err(h2_to_io_error) {
+						Ok(v) => v,
+						Err(e) => break Err(e),
+					};
+
+					if req.method() != Method::CONNECT {
+						reject(respond, StatusCode::METHOD_NOT_ALLOWED);
+						continue;
+					}
+					let is_websocket = req
+						.extensions()
+						.get::<h2::ext::Protocol>()
+						.is_some_and(|p| p == &h2::ext::Protocol::from_static("websocket"));
+
+					let recv_stream = req.into_body();
+					match accept(respond) {
+						Ok(send_stream) => {
+							self.active_count.fetch_add(1, Ordering::SeqCst);
+							let stream_metrics = self.metrics.clone().map(|sink| (StreamMetricsState::new(), sink));
+							let (d1, d2) = DropCounter::new(Arc::clone(&self.active_count), stream_metrics.clone());
+							let stream = H2Stream {
+								read: H2StreamReadHalf {
+									recv_stream,
+									_dropped: d1,
+									conn_bytes_counter: self.bytes_counter.clone(),
+									conn_data_received: self.data_received.clone(),
+									metrics: stream_metrics.as_ref().map(|(state, _)| state.clone()),
+								},
+								write: H2StreamWriteHalf {
+									send_stream,
+									_dropped: d2,
+									metrics: stream_metrics.map(|(state, _)| state),
+								},
+							};
+							handler(stream, is_websocket);
+						},
+						Err(e) => debug!("failed to accept tunnel: {e}"),
+					}
+				},
+				_ = &mut self.ping_rx => {
+					trace!("server connection exiting due to ping timeout");
+					break Ok(());
+				},
+				Some(target) = async { self.window_updates.as_mut()?.recv().await }, if self.window_updates.is_some() => {
+					self.connection.set_target_window_size(target);
+					let _ = self.connection.set_initial_window_size(target);
+				},
+				Some(deadline) = async { drain_rx.as_mut()?.await.ok() }, if drain_rx.is_some() => {
+					drain_rx = None;
+					info!("draining connection, waiting up to {deadline:?} for {} active streams", self.active_count.load(Ordering::Relaxed));
+					self.connection.graceful_shutdown();
+					drain_deadline = Some(Box::pin(tokio::time::sleep(deadline)));
+				},
+				_ = async { drain_deadline.as_mut().unwrap().await }, if drain_deadline.is_some() => {
+					trace!("drain deadline elapsed with streams still active, closing the connection out from under them");
+					break Ok(());
+				},
+			}
+
+			if drain_deadline.is_some() && self.active_count.load(Ordering::Relaxed) == 0 {
+				trace!("all streams finished draining");
+				break Ok(());
+			}
+		};
+		self.dropped.store(true, Ordering::Relaxed);
+		result
+	}
+}
+
+fn accept(mut respond: SendResponse<Bytes>) -> Result<h2::SendStream<Bytes>, Error> {
+	let response = Response::builder()
+		.status(StatusCode::OK)
+		.body(())
+		.map_err(Error::other)?;
+	respond.send_response(response, false).map_err(h2_to_io_error)
+}
+
+fn reject(mut respond: SendResponse<Bytes>, status: StatusCode) {
+	if let Ok(response) = Response::builder().status(status).body(()) {
+		let _ = respond.send_response(response, true);
+	}
+}